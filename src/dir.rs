@@ -0,0 +1,112 @@
+// Copyright (C) 2021 Andrej Shadura
+// SPDX-License-Identifier: MIT
+use crate::{Flock, OpenAndLock};
+use std::fs::{create_dir_all, OpenOptions};
+use std::io::Result;
+use std::path::{Path, PathBuf};
+
+/// Options for [`lock_dir`].
+pub struct DirLockOptions {
+    /// Take an exclusive lock if `true`, a shared one otherwise. Defaults to
+    /// `true`.
+    pub exclusive: bool,
+    /// Fail immediately with [`std::io::ErrorKind::WouldBlock`] instead of
+    /// waiting for the lock to become available. Defaults to `false`.
+    pub non_blocking: bool,
+    /// Name of the sentinel lock file created inside the locked directory.
+    /// Defaults to `.lock`.
+    pub file_name: PathBuf,
+}
+
+impl Default for DirLockOptions {
+    fn default() -> Self {
+        DirLockOptions {
+            exclusive: true,
+            non_blocking: false,
+            file_name: PathBuf::from(".lock"),
+        }
+    }
+}
+
+/// Locks a whole directory rather than a single file.
+///
+/// Many tools need to treat a directory — a cache or a spool — as the unit
+/// of synchronisation. This creates the directory if it doesn't exist yet,
+/// then opens (or creates) a sentinel file inside it named by
+/// [`DirLockOptions::file_name`] and runs it through the same
+/// [`flopen`][]-style open-lock-recheck algorithm as [`OpenAndLock`], so the
+/// directory lock survives the sentinel being recreated out from under it.
+/// The lock is released when the returned [`Flock`] guard is dropped.
+///
+/// [`flopen`]: https://manpages.debian.org/flopen
+pub fn lock_dir<P: AsRef<Path>>(path: P, options: DirLockOptions) -> Result<Flock> {
+    create_dir_all(&path)?;
+    let sentinel = path.as_ref().join(&options.file_name);
+
+    let mut open_options = OpenOptions::new();
+    open_options.read(true).write(true).create(true);
+
+    match (options.exclusive, options.non_blocking) {
+        (true, false) => open_options.open_and_lock(sentinel),
+        (true, true) => open_options.try_open_and_lock(sentinel),
+        (false, false) => open_options.open_and_lock_shared(sentinel),
+        (false, true) => open_options.try_open_and_lock_shared(sentinel),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{lock_dir, DirLockOptions};
+    use std::io;
+    use tempfile::tempdir;
+
+    #[test]
+    fn locks_sentinel_inside_new_directory() {
+        let dir = tempdir().unwrap();
+        let mut spool_dir = dir.path().to_owned();
+        spool_dir.push("spool");
+
+        let lock = lock_dir(&spool_dir, DirLockOptions::default()).unwrap();
+
+        assert!(spool_dir.join(".lock").is_file());
+        assert_eq!(lock.path(), spool_dir.join(".lock"));
+    }
+
+    #[test]
+    fn second_exclusive_lock_is_blocked() {
+        let dir = tempdir().unwrap();
+        let mut spool_dir = dir.path().to_owned();
+        spool_dir.push("spool");
+
+        let _first = lock_dir(&spool_dir, DirLockOptions::default()).unwrap();
+
+        let error = lock_dir(
+            &spool_dir,
+            DirLockOptions {
+                non_blocking: true,
+                ..DirLockOptions::default()
+            },
+        )
+        .unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn custom_sentinel_file_name() {
+        let dir = tempdir().unwrap();
+        let mut spool_dir = dir.path().to_owned();
+        spool_dir.push("spool");
+
+        let _lock = lock_dir(
+            &spool_dir,
+            DirLockOptions {
+                file_name: "spool.lock".into(),
+                ..DirLockOptions::default()
+            },
+        )
+        .unwrap();
+
+        assert!(spool_dir.join("spool.lock").is_file());
+    }
+}
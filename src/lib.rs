@@ -1,26 +1,38 @@
 // Copyright (C) 2021 Andrej Shadura
 // SPDX-License-Identifier: MIT
-use nix::fcntl::{flock, FlockArg};
-use std::fs::{metadata, File, OpenOptions};
-use std::io::Result;
-use std::os::unix::{fs::MetadataExt, io::AsRawFd};
-use std::path::Path;
+mod dir;
+mod sys;
+
+pub use dir::{lock_dir, DirLockOptions};
+
+use std::fs::{File, OpenOptions};
+use std::io::{self, Result};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::{Duration, Instant};
+use sys::{LockKind, SameFile};
+
+/// The backoff between successive lock attempts in
+/// [`OpenAndLock::open_and_lock_timeout`] is doubled after every failed
+/// attempt, up to this cap.
+const MAX_RETRY_BACKOFF: Duration = Duration::from_millis(500);
 
 /// This trait provides a way to reliably open and lock a file
 ///
 /// `OpenAndLock` trait provides methods implementing the algorithm of the
 /// [`flopen`][] function available on BSD systems. It is roughly equivalent
-/// to opening a file and calling [`flock`][] with an `operation` argument
-/// set to `LOCK_EX`, but it also attempts to detect and handle races between
-/// opening or creating the file and locking it. This makes it well-suited
-/// for opening lock files, PID files, spool files, mailboxes and other kinds
-/// of files which are used for synchronisation between processes.
+/// to opening a file and taking an exclusive advisory lock on it ([`flock`][]
+/// with `LOCK_EX` on Linux, `LockFileEx` on Windows), but it also attempts to
+/// detect and handle races between opening or creating the file and locking
+/// it. This makes it well-suited for opening lock files, PID files, spool
+/// files, mailboxes and other kinds of files which are used for
+/// synchronisation between processes.
 ///
 /// This trait extends [`OpenOptions`], so it can be used the following way:
 /// ```no_run
 /// # use flopen::OpenAndLock;
 /// # use std::fs::OpenOptions;
-/// let file = OpenOptions::new()
+/// let lock = OpenOptions::new()
 ///     .read(true)
 ///     .write(true)
 ///     .create(true)
@@ -35,12 +47,13 @@ pub trait OpenAndLock {
     ///
     /// Opens a file and locks it in an exclusive mode, blocking until the lock
     /// is possible. Retries if the file disappeared or recreated immediately after
-    /// locking.
+    /// locking. The lock is held for as long as the returned [`Flock`] guard
+    /// lives.
     ///
     /// This method waits until the file can be locked, so unless an unrelated I/O
     /// error occurs, it will eventually succeed once the file has been released
     /// if it’s been held by a different process.
-    fn open_and_lock<P: AsRef<Path>>(&self, path: P) -> Result<File>;
+    fn open_and_lock<P: AsRef<Path>>(&self, path: P) -> Result<Flock>;
 
     /// Try to open and lock a file.
     ///
@@ -51,38 +64,233 @@ pub trait OpenAndLock {
     ///
     /// This method returns an error immediately when the file cannot be
     /// locked, allowing the called to handle it and retry if necessary.
-    fn try_open_and_lock<P: AsRef<Path>>(&self, path: P) -> Result<File>;
+    fn try_open_and_lock<P: AsRef<Path>>(&self, path: P) -> Result<Flock>;
+
+    /// Open and lock a file in shared mode.
+    ///
+    /// Opens a file and locks it in a shared mode, blocking until the lock
+    /// is possible. Retries if the file disappeared or recreated immediately after
+    /// locking.
+    ///
+    /// Shared locks allow any number of readers to hold the lock at once, while
+    /// still excluding an exclusive (writer) lock. This method waits until the
+    /// file can be locked, so unless an unrelated I/O error occurs, it will
+    /// eventually succeed once any conflicting exclusive lock is released.
+    fn open_and_lock_shared<P: AsRef<Path>>(&self, path: P) -> Result<Flock>;
+
+    /// Try to open and lock a file in shared mode.
+    ///
+    /// Opens a file and locks it in a shared mode, failing with
+    /// [`std::io::ErrorKind::WouldBlock`] if the lock is not possible.
+    /// Retries if the file disappeared or recreated immediately after
+    /// locking.
+    ///
+    /// This method returns an error immediately when the file cannot be
+    /// locked, allowing the called to handle it and retry if necessary.
+    fn try_open_and_lock_shared<P: AsRef<Path>>(&self, path: P) -> Result<Flock>;
+
+    /// Open and lock a file, run `f` with it, then release the lock.
+    ///
+    /// This spares the caller from managing a [`Flock`] guard's lifetime for
+    /// the common "lock, do some work, unlock" pattern: the lock is held for
+    /// the duration of `f` and released as soon as it returns, even if `f`
+    /// returns early.
+    fn open_and_lock_with<P: AsRef<Path>, R>(
+        &self,
+        path: P,
+        f: impl FnOnce(&mut File) -> R,
+    ) -> Result<R> {
+        let mut lock = self.open_and_lock(path)?;
+        Ok(f(lock.file_mut()))
+    }
+
+    /// Try to open and lock a file, run `f` with it, then release the lock.
+    ///
+    /// Fails with [`std::io::ErrorKind::WouldBlock`] if the lock is not
+    /// possible, without calling `f`.
+    fn try_open_and_lock_with<P: AsRef<Path>, R>(
+        &self,
+        path: P,
+        f: impl FnOnce(&mut File) -> R,
+    ) -> Result<R> {
+        let mut lock = self.try_open_and_lock(path)?;
+        Ok(f(lock.file_mut()))
+    }
+
+    /// Open and lock a file in shared mode, run `f` with it, then release the lock.
+    fn open_and_lock_shared_with<P: AsRef<Path>, R>(
+        &self,
+        path: P,
+        f: impl FnOnce(&mut File) -> R,
+    ) -> Result<R> {
+        let mut lock = self.open_and_lock_shared(path)?;
+        Ok(f(lock.file_mut()))
+    }
+
+    /// Try to open and lock a file in shared mode, run `f` with it, then release the lock.
+    ///
+    /// Fails with [`std::io::ErrorKind::WouldBlock`] if the lock is not
+    /// possible, without calling `f`.
+    fn try_open_and_lock_shared_with<P: AsRef<Path>, R>(
+        &self,
+        path: P,
+        f: impl FnOnce(&mut File) -> R,
+    ) -> Result<R> {
+        let mut lock = self.try_open_and_lock_shared(path)?;
+        Ok(f(lock.file_mut()))
+    }
+
+    /// Open and lock a file, waiting up to `timeout` for the lock to become
+    /// available.
+    ///
+    /// Repeatedly attempts a non-blocking lock, sleeping with exponential
+    /// backoff between attempts, and fails with
+    /// [`std::io::ErrorKind::WouldBlock`] if `timeout` elapses before the
+    /// lock can be taken.
+    fn open_and_lock_timeout<P: AsRef<Path>>(&self, path: P, timeout: Duration) -> Result<Flock> {
+        self.open_and_lock_timeout_with(path, timeout, || {})
+    }
+
+    /// Like [`OpenAndLock::open_and_lock_timeout`], but calls `on_wait` once
+    /// per wait iteration.
+    ///
+    /// This lets long-running tools drive a spinner or progress indicator
+    /// while waiting for a contended lock.
+    fn open_and_lock_timeout_with<P: AsRef<Path>>(
+        &self,
+        path: P,
+        timeout: Duration,
+        mut on_wait: impl FnMut(),
+    ) -> Result<Flock> {
+        let deadline = Instant::now() + timeout;
+        let mut backoff = Duration::from_millis(1);
+        loop {
+            match self.try_open_and_lock(&path) {
+                Ok(lock) => return Ok(lock),
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock => {
+                    let remaining = deadline.saturating_duration_since(Instant::now());
+                    if remaining.is_zero() {
+                        return Err(err);
+                    }
+                    on_wait();
+                    thread::sleep(backoff.min(remaining));
+                    backoff = (backoff * 2).min(MAX_RETRY_BACKOFF);
+                }
+                Err(err) => return Err(err),
+            }
+        }
+    }
+}
+
+/// An RAII guard around a file locked with an advisory, platform-native lock.
+///
+/// The lock is released automatically when the guard is dropped. Use
+/// [`Flock::unlock`] to release the lock early while keeping the file open,
+/// or [`Flock::relock_shared`]/[`Flock::relock_exclusive`] to change the lock
+/// mode without closing and reopening the file.
+#[derive(Debug)]
+pub struct Flock {
+    file: Option<File>,
+    path: PathBuf,
+}
+
+impl Flock {
+    fn new(file: File, path: PathBuf) -> Flock {
+        Flock {
+            file: Some(file),
+            path,
+        }
+    }
+
+    /// Returns the path the file was opened at.
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Returns a reference to the locked file.
+    pub fn file(&self) -> &File {
+        self.file.as_ref().expect("Flock file taken before drop")
+    }
+
+    /// Returns a mutable reference to the locked file.
+    pub fn file_mut(&mut self) -> &mut File {
+        self.file.as_mut().expect("Flock file taken before drop")
+    }
+
+    /// Releases the lock and returns the underlying file, leaving it open.
+    pub fn unlock(mut self) -> File {
+        let file = self.file.take().expect("Flock file taken before drop");
+        let _ = sys::unlock(&file);
+        file
+    }
+
+    /// Downgrades the lock to shared mode.
+    ///
+    /// On Unix this is a single `flock(2)`/`fcntl(2)` call on the held lock.
+    /// With `fcntl(2)` (Solaris, Redox) the lock is converted in place;
+    /// `flock(2)` does not guarantee an atomic conversion, as the existing
+    /// lock may be removed before the new one is placed. On Windows,
+    /// `LockFileEx` cannot change the mode of a range it already holds, so
+    /// this unlocks and re-locks the file. Where the conversion is not atomic,
+    /// there is a brief window in which another process could acquire the
+    /// lock first.
+    pub fn relock_shared(&mut self) -> Result<()> {
+        sys::lock(self.file(), LockKind::Shared, false)
+    }
+
+    /// Upgrades the lock to exclusive mode.
+    ///
+    /// On Unix this is a single `flock(2)`/`fcntl(2)` call on the held lock.
+    /// With `fcntl(2)` (Solaris, Redox) the lock is converted in place;
+    /// `flock(2)` does not guarantee an atomic conversion, as the existing
+    /// lock may be removed before the new one is placed. On Windows,
+    /// `LockFileEx` cannot change the mode of a range it already holds, so
+    /// this unlocks and re-locks the file. Where the conversion is not atomic,
+    /// there is a brief window in which another process could acquire the
+    /// lock first.
+    pub fn relock_exclusive(&mut self) -> Result<()> {
+        sys::lock(self.file(), LockKind::Exclusive, false)
+    }
+}
+
+impl Drop for Flock {
+    fn drop(&mut self) {
+        if let Some(file) = self.file.take() {
+            let _ = sys::unlock(&file);
+        }
+    }
 }
 
 fn open_and_lock<P: AsRef<Path>>(
     options: &OpenOptions,
     path: P,
-    lock_mode: FlockArg,
-) -> Result<File> {
+    kind: LockKind,
+    non_blocking: bool,
+) -> Result<Flock> {
     loop {
         let file = options.open(&path)?;
-        flock(file.as_raw_fd(), lock_mode)?;
-        if let Ok(metadata_at_path) = metadata(&path) {
-            let file_metadata = file.metadata()?;
-            if metadata_at_path.dev() != file_metadata.dev()
-                || metadata_at_path.ino() != file_metadata.ino()
-            {
-                continue;
-            }
-            return Ok(file);
-        } else {
-            continue;
+        sys::lock(&file, kind, non_blocking)?;
+        if file.is_same_file(path.as_ref())? {
+            return Ok(Flock::new(file, path.as_ref().to_owned()));
         }
     }
 }
 
 impl OpenAndLock for OpenOptions {
-    fn open_and_lock<P: AsRef<Path>>(&self, path: P) -> Result<File> {
-        open_and_lock(self, path, FlockArg::LockExclusive)
+    fn open_and_lock<P: AsRef<Path>>(&self, path: P) -> Result<Flock> {
+        open_and_lock(self, path, LockKind::Exclusive, false)
+    }
+
+    fn try_open_and_lock<P: AsRef<Path>>(&self, path: P) -> Result<Flock> {
+        open_and_lock(self, path, LockKind::Exclusive, true)
+    }
+
+    fn open_and_lock_shared<P: AsRef<Path>>(&self, path: P) -> Result<Flock> {
+        open_and_lock(self, path, LockKind::Shared, false)
     }
 
-    fn try_open_and_lock<P: AsRef<Path>>(&self, path: P) -> Result<File> {
-        open_and_lock(self, path, FlockArg::LockExclusiveNonblock)
+    fn try_open_and_lock_shared<P: AsRef<Path>>(&self, path: P) -> Result<Flock> {
+        open_and_lock(self, path, LockKind::Shared, true)
     }
 }
 
@@ -99,7 +307,7 @@ mod tests {
         let mut lock_path = dir.path().to_owned();
         lock_path.push("foo.lock");
 
-        let _file = OpenOptions::new()
+        let _lock = OpenOptions::new()
             .read(true)
             .write(true)
             .create(true)
@@ -115,4 +323,192 @@ mod tests {
 
         assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
     }
+
+    #[test]
+    fn shared_locks_do_not_conflict() {
+        let dir = tempdir().unwrap();
+        let mut lock_path = dir.path().to_owned();
+        lock_path.push("foo.lock");
+
+        let _first = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open_and_lock_shared(&lock_path)
+            .unwrap();
+
+        let _second = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .try_open_and_lock_shared(&lock_path)
+            .unwrap();
+    }
+
+    #[test]
+    fn shared_lock_blocks_exclusive() {
+        let dir = tempdir().unwrap();
+        let mut lock_path = dir.path().to_owned();
+        lock_path.push("foo.lock");
+
+        let _reader = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open_and_lock_shared(&lock_path)
+            .unwrap();
+
+        let error = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .try_open_and_lock(&lock_path)
+            .unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn unlock_releases_lock_but_keeps_file_open() {
+        let dir = tempdir().unwrap();
+        let mut lock_path = dir.path().to_owned();
+        lock_path.push("foo.lock");
+
+        let lock = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open_and_lock(&lock_path)
+            .unwrap();
+
+        let _file = lock.unlock();
+
+        let _other = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .try_open_and_lock(&lock_path)
+            .unwrap();
+    }
+
+    #[test]
+    fn drop_releases_lock() {
+        let dir = tempdir().unwrap();
+        let mut lock_path = dir.path().to_owned();
+        lock_path.push("foo.lock");
+
+        {
+            let _lock = OpenOptions::new()
+                .read(true)
+                .write(true)
+                .create(true)
+                .open_and_lock(&lock_path)
+                .unwrap();
+        }
+
+        let _other = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .try_open_and_lock(&lock_path)
+            .unwrap();
+    }
+
+    #[test]
+    fn relock_shared_allows_second_reader() {
+        let dir = tempdir().unwrap();
+        let mut lock_path = dir.path().to_owned();
+        lock_path.push("foo.lock");
+
+        let mut lock = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open_and_lock(&lock_path)
+            .unwrap();
+
+        lock.relock_shared().unwrap();
+
+        let _reader = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .try_open_and_lock_shared(&lock_path)
+            .unwrap();
+    }
+
+    #[test]
+    fn open_and_lock_with_releases_after_closure() {
+        use std::io::Write;
+
+        let dir = tempdir().unwrap();
+        let mut lock_path = dir.path().to_owned();
+        lock_path.push("foo.lock");
+
+        let written = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open_and_lock_with(&lock_path, |file| file.write_all(b"hello").is_ok())
+            .unwrap();
+        assert!(written);
+
+        let _other = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .try_open_and_lock(&lock_path)
+            .unwrap();
+    }
+
+    #[test]
+    fn open_and_lock_timeout_times_out_when_contended() {
+        use std::time::Duration;
+
+        let dir = tempdir().unwrap();
+        let mut lock_path = dir.path().to_owned();
+        lock_path.push("foo.lock");
+
+        let _holder = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open_and_lock(&lock_path)
+            .unwrap();
+
+        let mut waits = 0;
+        let error = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open_and_lock_timeout_with(&lock_path, Duration::from_millis(50), || waits += 1)
+            .unwrap_err();
+
+        assert_eq!(error.kind(), io::ErrorKind::WouldBlock);
+        assert!(waits > 0);
+    }
+
+    #[test]
+    fn open_and_lock_timeout_succeeds_once_released() {
+        use std::time::Duration;
+
+        let dir = tempdir().unwrap();
+        let mut lock_path = dir.path().to_owned();
+        lock_path.push("foo.lock");
+
+        let holder = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open_and_lock(&lock_path)
+            .unwrap();
+        drop(holder);
+
+        let _lock = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .open_and_lock_timeout(&lock_path, Duration::from_millis(100))
+            .unwrap();
+    }
 }
@@ -0,0 +1,55 @@
+// Copyright (C) 2021 Andrej Shadura
+// SPDX-License-Identifier: MIT
+
+//! Platform-specific locking backends.
+//!
+//! Each backend provides the same `lock`/`unlock` functions and a
+//! [`SameFile`] implementation for [`File`][std::fs::File], so the
+//! open-lock-recheck algorithm in the crate root stays platform-agnostic.
+
+#[cfg(unix)]
+mod unix_common;
+
+// `flock(2)` is available on every Unix `nix` supports except Solaris and
+// Redox (see `nix::fcntl::flock`), so it covers Linux and the BSDs/macOS
+// too. Only the genuinely flock-less platforms fall back to `fcntl(2)`
+// POSIX record locks, whose per-`(process, inode)` semantics (rather than
+// per-fd) would otherwise silently drop locks held by unrelated file
+// descriptors on the same file.
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "redox"))))]
+mod unix_flock;
+#[cfg(all(unix, not(any(target_os = "solaris", target_os = "redox"))))]
+pub(crate) use unix_flock::{lock, unlock};
+
+#[cfg(all(unix, any(target_os = "solaris", target_os = "redox")))]
+mod unix_fcntl;
+#[cfg(all(unix, any(target_os = "solaris", target_os = "redox")))]
+pub(crate) use unix_fcntl::{lock, unlock};
+
+#[cfg(windows)]
+mod windows;
+#[cfg(windows)]
+pub(crate) use windows::{lock, unlock};
+
+use std::io::Result;
+use std::path::Path;
+
+/// The kind of advisory lock requested from the operating system.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum LockKind {
+    Shared,
+    Exclusive,
+}
+
+/// Checks whether an open file handle still refers to the file present at a
+/// given path.
+///
+/// This is the platform-specific half of the flopen retry loop: on Unix it
+/// compares `dev`/`ino`, while on Windows, where those don't exist, it
+/// compares the volume serial number and file index from
+/// `BY_HANDLE_FILE_INFORMATION`. A mismatch (or the path no longer existing)
+/// means the file was replaced between opening and locking it, and the
+/// caller should retry.
+pub(crate) trait SameFile {
+    fn is_same_file(&self, path: &Path) -> Result<bool>;
+}
@@ -0,0 +1,21 @@
+// Copyright (C) 2021 Andrej Shadura
+// SPDX-License-Identifier: MIT
+
+//! Bits shared by every Unix backend, regardless of whether locking is done
+//! with `flock(2)` or `fcntl(2)`.
+use super::SameFile;
+use std::fs::{metadata, File};
+use std::io::Result;
+use std::os::unix::fs::MetadataExt;
+use std::path::Path;
+
+impl SameFile for File {
+    fn is_same_file(&self, path: &Path) -> Result<bool> {
+        let path_metadata = match metadata(path) {
+            Ok(metadata) => metadata,
+            Err(_) => return Ok(false),
+        };
+        let file_metadata = self.metadata()?;
+        Ok(path_metadata.dev() == file_metadata.dev() && path_metadata.ino() == file_metadata.ino())
+    }
+}
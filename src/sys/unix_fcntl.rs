@@ -0,0 +1,40 @@
+// Copyright (C) 2021 Andrej Shadura
+// SPDX-License-Identifier: MIT
+use super::LockKind;
+use nix::fcntl::{fcntl, FcntlArg};
+use std::fs::File;
+use std::io::Result;
+use std::mem::MaybeUninit;
+use std::os::unix::io::AsRawFd;
+
+/// `flock(2)` isn't available on every Unix, so the platforms without it
+/// (Solaris and Redox) use `fcntl(2)` POSIX record locks over the whole file
+/// instead.
+fn whole_file_lock(l_type: libc::c_short) -> libc::flock {
+    let mut lock: libc::flock = unsafe { MaybeUninit::zeroed().assume_init() };
+    lock.l_type = l_type;
+    lock.l_whence = libc::SEEK_SET as libc::c_short;
+    lock.l_start = 0;
+    lock.l_len = 0;
+    lock
+}
+
+pub(crate) fn lock(file: &File, kind: LockKind, non_blocking: bool) -> Result<()> {
+    let l_type = match kind {
+        LockKind::Shared => libc::F_RDLCK as libc::c_short,
+        LockKind::Exclusive => libc::F_WRLCK as libc::c_short,
+    };
+    let lock = whole_file_lock(l_type);
+    if non_blocking {
+        fcntl(file.as_raw_fd(), FcntlArg::F_SETLK(&lock))?;
+    } else {
+        fcntl(file.as_raw_fd(), FcntlArg::F_SETLKW(&lock))?;
+    }
+    Ok(())
+}
+
+pub(crate) fn unlock(file: &File) -> Result<()> {
+    let lock = whole_file_lock(libc::F_UNLCK as libc::c_short);
+    fcntl(file.as_raw_fd(), FcntlArg::F_SETLK(&lock))?;
+    Ok(())
+}
@@ -0,0 +1,23 @@
+// Copyright (C) 2021 Andrej Shadura
+// SPDX-License-Identifier: MIT
+use super::LockKind;
+use nix::fcntl::{flock, FlockArg};
+use std::fs::File;
+use std::io::Result;
+use std::os::unix::io::AsRawFd;
+
+pub(crate) fn lock(file: &File, kind: LockKind, non_blocking: bool) -> Result<()> {
+    let arg = match (kind, non_blocking) {
+        (LockKind::Shared, false) => FlockArg::LockShared,
+        (LockKind::Shared, true) => FlockArg::LockSharedNonblock,
+        (LockKind::Exclusive, false) => FlockArg::LockExclusive,
+        (LockKind::Exclusive, true) => FlockArg::LockExclusiveNonblock,
+    };
+    flock(file.as_raw_fd(), arg)?;
+    Ok(())
+}
+
+pub(crate) fn unlock(file: &File) -> Result<()> {
+    flock(file.as_raw_fd(), FlockArg::Unlock)?;
+    Ok(())
+}
@@ -0,0 +1,78 @@
+// Copyright (C) 2021 Andrej Shadura
+// SPDX-License-Identifier: MIT
+use super::{LockKind, SameFile};
+use std::fs::{File, OpenOptions};
+use std::io::{Error, Result};
+use std::mem::zeroed;
+use std::os::windows::io::AsRawHandle;
+use std::path::Path;
+use windows_sys::Win32::Foundation::HANDLE;
+use windows_sys::Win32::Storage::FileSystem::{
+    GetFileInformationByHandle, LockFileEx, UnlockFile, BY_HANDLE_FILE_INFORMATION,
+    LOCKFILE_EXCLUSIVE_LOCK, LOCKFILE_FAIL_IMMEDIATELY,
+};
+use windows_sys::Win32::System::IO::OVERLAPPED;
+
+pub(crate) fn lock(file: &File, kind: LockKind, non_blocking: bool) -> Result<()> {
+    // `LockFileEx` refuses to change the mode of a range the caller already
+    // holds a lock on (relocking fails with `ERROR_LOCK_VIOLATION`), unlike
+    // `flock(2)`/`fcntl(2)` which allow a held lock to be converted in
+    // place. Drop any existing lock first so `relock_shared`/
+    // `relock_exclusive` can re-acquire it in the new mode; there's a short
+    // window where another process could grab the lock in between, so
+    // unlike the `fcntl(2)` backend this isn't atomic.
+    let _ = unlock(file);
+    let mut flags = 0;
+    if kind == LockKind::Exclusive {
+        flags |= LOCKFILE_EXCLUSIVE_LOCK;
+    }
+    if non_blocking {
+        flags |= LOCKFILE_FAIL_IMMEDIATELY;
+    }
+    let mut overlapped: OVERLAPPED = unsafe { zeroed() };
+    let ok = unsafe {
+        LockFileEx(
+            file.as_raw_handle() as HANDLE,
+            flags,
+            0,
+            !0,
+            !0,
+            &mut overlapped,
+        )
+    };
+    if ok == 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+pub(crate) fn unlock(file: &File) -> Result<()> {
+    let ok = unsafe { UnlockFile(file.as_raw_handle() as HANDLE, 0, 0, !0, !0) };
+    if ok == 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(())
+}
+
+fn file_identity(file: &File) -> Result<BY_HANDLE_FILE_INFORMATION> {
+    let mut info: BY_HANDLE_FILE_INFORMATION = unsafe { zeroed() };
+    let ok = unsafe { GetFileInformationByHandle(file.as_raw_handle() as HANDLE, &mut info) };
+    if ok == 0 {
+        return Err(Error::last_os_error());
+    }
+    Ok(info)
+}
+
+impl SameFile for File {
+    fn is_same_file(&self, path: &Path) -> Result<bool> {
+        let other = match OpenOptions::new().read(true).open(path) {
+            Ok(other) => other,
+            Err(_) => return Ok(false),
+        };
+        let this_info = file_identity(self)?;
+        let other_info = file_identity(&other)?;
+        Ok(this_info.dwVolumeSerialNumber == other_info.dwVolumeSerialNumber
+            && this_info.nFileIndexHigh == other_info.nFileIndexHigh
+            && this_info.nFileIndexLow == other_info.nFileIndexLow)
+    }
+}